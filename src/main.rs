@@ -4,10 +4,19 @@ use futures::stream::FuturesUnordered;
 use opentelemetry::trace::SpanKind;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use clap::Parser;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry as PromRegistry, TextEncoder,
+};
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
 use tonic::metadata::{MetadataMap, MetadataValue};
 use tracing::info_span;
 use tracing_futures::Instrument;
@@ -15,7 +24,82 @@ use tracing_subscriber::filter::EnvFilter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::Registry;
 use warp::reject::{self, Rejection};
-use warp::Filter;
+use warp::{Filter, Reply};
+
+/// Command-line configuration for the proxy.
+#[derive(Parser)]
+struct Args {
+    /// Restrict service to these ZIP codes; if any are given, all others are
+    /// rejected. May be repeated.
+    #[clap(long = "allow-zip")]
+    allow_zip: Vec<String>,
+
+    /// ZIP codes to reject outright. May be repeated.
+    #[clap(long = "block-zip")]
+    block_zip: Vec<String>,
+
+    /// Store numbers whose `checkSlots` requests should never be issued. May be
+    /// repeated.
+    #[clap(long = "block-store")]
+    block_store: Vec<i32>,
+
+    /// Domain to obtain a Let's Encrypt certificate for. When set, the server
+    /// listens with TLS instead of plaintext.
+    #[clap(long = "acme-domain")]
+    acme_domain: Option<String>,
+
+    /// Directory in which issued certificates and the ACME account key are
+    /// cached between restarts.
+    #[clap(long = "acme-cache-dir", default_value = "acme-cache")]
+    acme_cache_dir: PathBuf,
+}
+
+/// Startup allow/deny lists consulted before any RiteAid call, letting an
+/// operator restrict the proxy to a supported region and suppress stores known
+/// to return bad data.
+struct Filters {
+    allow_zip: Option<HashSet<String>>,
+    block_zip: HashSet<String>,
+    block_store: HashSet<i32>,
+}
+
+impl Filters {
+    fn from_args(args: Args) -> Self {
+        let allow_zip = if args.allow_zip.is_empty() {
+            None
+        } else {
+            Some(args.allow_zip.into_iter().collect())
+        };
+
+        Filters {
+            allow_zip,
+            block_zip: args.block_zip.into_iter().collect(),
+            block_store: args.block_store.into_iter().collect(),
+        }
+    }
+
+    fn zip_allowed(&self, zip: &str) -> bool {
+        if self.block_zip.contains(zip) {
+            return false;
+        }
+
+        match &self.allow_zip {
+            Some(allow) => allow.contains(zip),
+            None => true,
+        }
+    }
+
+    fn store_allowed(&self, store_number: i32) -> bool {
+        !self.block_store.contains(&store_number)
+    }
+}
+
+/// A ZIP code rejected by the configured allow/deny lists. Recovered into a
+/// `403 Forbidden` response.
+#[derive(Debug)]
+struct Blocked;
+
+impl warp::reject::Reject for Blocked {}
 
 #[derive(Debug)]
 struct Err(anyhow::Error);
@@ -56,6 +140,69 @@ struct Store {
     phone: String,
 }
 
+/// TTL cache of `getStores` responses keyed by ZIP code. Entries older than the
+/// configured TTL are treated as a miss and re-fetched, a background sweeper
+/// evicts them, and a per-key `Mutex` coalesces concurrent refreshes for the
+/// same ZIP so only one request hits the upstream while the rest await it.
+struct StoreCache {
+    ttl: Duration,
+    entries: DashMap<String, (GetStoresResponse, Instant)>,
+    locks: DashMap<String, Arc<Mutex<()>>>,
+}
+
+impl StoreCache {
+    fn new(ttl: Duration) -> Self {
+        StoreCache {
+            ttl,
+            entries: DashMap::new(),
+            locks: DashMap::new(),
+        }
+    }
+
+    /// Returns the cached response for `zip_code` only if it is younger than the
+    /// TTL; an expired entry is reported as a miss.
+    fn get_fresh(&self, zip_code: &str) -> Option<GetStoresResponse> {
+        let entry = self.entries.get(zip_code)?;
+        if entry.1.elapsed() < self.ttl {
+            Some(entry.0.clone())
+        } else {
+            None
+        }
+    }
+
+    fn insert(&self, zip_code: String, response: GetStoresResponse) {
+        self.entries.insert(zip_code, (response, Instant::now()));
+    }
+
+    /// The single-flight lock for a ZIP, created on first use.
+    fn lock_for(&self, zip_code: &str) -> Arc<Mutex<()>> {
+        self.locks
+            .entry(zip_code.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone()
+    }
+
+    /// Drop every entry whose TTL has elapsed, and any single-flight lock that
+    /// is neither in-flight (held by a fetcher) nor backing a fresh entry, so
+    /// neither map grows without bound under arbitrary ZIP strings.
+    fn sweep(&self) {
+        self.entries
+            .retain(|_, (_, inserted)| inserted.elapsed() < self.ttl);
+        self.locks
+            .retain(|zip, lock| Arc::strong_count(lock) > 1 || self.entries.contains_key(zip));
+    }
+}
+
+/// Periodically evicts expired entries so the cache doesn't grow without bound.
+async fn cache_sweeper(store_cache: Arc<StoreCache>) {
+    let mut ticker = tokio::time::interval(store_cache.ttl);
+
+    loop {
+        ticker.tick().await;
+        store_cache.sweep();
+    }
+}
+
 #[derive(Deserialize)]
 struct CheckSlotsResponse {
     #[serde(rename = "Data")]
@@ -76,11 +223,152 @@ struct AvailabilityResponse {
     phone: String,
 }
 
+/// Emitted as a trailing NDJSON line when an individual store's `checkSlots`
+/// future fails, so one bad store doesn't drop the rest of the stream.
+#[derive(Serialize)]
+struct StreamError {
+    error: String,
+}
+
+/// Prometheus instrumentation for upstream RiteAid call health. Operators scrape
+/// `/metrics` to alert on API degradation without relying solely on Honeycomb
+/// traces.
+mod metrics {
+    use super::*;
+
+    pub static REGISTRY: Lazy<PromRegistry> = Lazy::new(PromRegistry::new);
+
+    pub static GET_STORES_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+        register(IntCounter::new(
+            "getstores_requests_total",
+            "Total getStores requests issued upstream",
+        ))
+    });
+
+    pub static CHECK_SLOTS_TOTAL: Lazy<IntCounter> = Lazy::new(|| {
+        register(IntCounter::new(
+            "checkslots_requests_total",
+            "Total checkSlots requests issued upstream",
+        ))
+    });
+
+    pub static CACHE_HITS: Lazy<IntCounter> = Lazy::new(|| {
+        register(IntCounter::new(
+            "store_cache_hits_total",
+            "list_stores lookups served from the cache",
+        ))
+    });
+
+    pub static CACHE_MISSES: Lazy<IntCounter> = Lazy::new(|| {
+        register(IntCounter::new(
+            "store_cache_misses_total",
+            "list_stores lookups that fell through to an upstream fetch",
+        ))
+    });
+
+    pub static UPSTREAM_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+        register(
+            IntCounterVec::new(
+                Opts::new("upstream_errors_total", "Upstream errors by kind"),
+                &["kind"],
+            )
+            .expect("valid metric"),
+        )
+    });
+
+    pub static CHECK_SLOTS_LATENCY: Lazy<Histogram> = Lazy::new(|| {
+        register(Histogram::with_opts(HistogramOpts::new(
+            "checkslots_latency_seconds",
+            "Per-store checkSlots request latency",
+        )))
+    });
+
+    pub static AVAILABLE_STORES: Lazy<IntGaugeVec> = Lazy::new(|| {
+        register(
+            IntGaugeVec::new(
+                Opts::new(
+                    "stores_possibly_available",
+                    "Stores reporting possible_availability on the last availability query for this ZIP",
+                ),
+                &["zip"],
+            )
+            .expect("valid metric"),
+        )
+    });
+
+    /// Register a collector with our registry, panicking on the programmer error
+    /// of a duplicate or malformed metric.
+    fn register<C>(collector: Result<C, prometheus::Error>) -> C
+    where
+        C: prometheus::core::Collector + Clone + 'static,
+    {
+        let collector = collector.expect("valid metric");
+        REGISTRY
+            .register(Box::new(collector.clone()))
+            .expect("metric registered once");
+        collector
+    }
+
+    /// Classify a reqwest error into a coarse label for the `kind` dimension.
+    pub fn record_upstream_error(err: &reqwest::Error) {
+        let kind = if err.is_timeout() {
+            "timeout"
+        } else if err.is_connect() {
+            "connect"
+        } else if err.is_decode() {
+            "decode"
+        } else if err.is_status() {
+            "status"
+        } else {
+            "other"
+        };
+
+        UPSTREAM_ERRORS.with_label_values(&[kind]).inc();
+    }
+
+    /// Force lazy registration of every metric so an unscraped counter still
+    /// appears (as zero) on the first `/metrics` request.
+    pub fn init() {
+        Lazy::force(&GET_STORES_TOTAL);
+        Lazy::force(&CHECK_SLOTS_TOTAL);
+        Lazy::force(&CACHE_HITS);
+        Lazy::force(&CACHE_MISSES);
+        Lazy::force(&UPSTREAM_ERRORS);
+        Lazy::force(&CHECK_SLOTS_LATENCY);
+        Lazy::force(&AVAILABLE_STORES);
+    }
+}
+
+async fn metrics_handler() -> Result<impl warp::Reply, Rejection> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&metrics::REGISTRY.gather(), &mut buffer)
+        .expect("encoding prometheus metrics cannot fail");
+
+    Ok(warp::http::Response::builder()
+        .header("content-type", encoder.format_type())
+        .body(buffer))
+}
+
 #[tokio::main]
 async fn main() {
+    metrics::init();
+
     let client = Client::new();
 
-    let store_cache = Arc::new(DashMap::new());
+    let args = Args::parse();
+    let acme_domain = args.acme_domain.clone();
+    let acme_cache_dir = args.acme_cache_dir.clone();
+    let filters = Arc::new(Filters::from_args(args));
+
+    let cache_ttl = std::env::var("STORE_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3600);
+    let store_cache = Arc::new(StoreCache::new(Duration::from_secs(cache_ttl)));
+
+    tokio::spawn(cache_sweeper(store_cache.clone()));
 
     if let Ok(api_key) = std::env::var("HONEYCOMB_API_KEY") {
         let mut tracing_headers = MetadataMap::new();
@@ -109,10 +397,50 @@ async fn main() {
         tracing::subscriber::set_global_default(subscriber).unwrap();
     }
 
-    let routes = warp::path!("availability" / String)
-        .map(move |s| (s, client.clone(), store_cache.clone()))
+    if let Ok(webhook_url) = std::env::var("WEBHOOK_URL") {
+        let zips: Vec<String> = std::env::var("WATCH_ZIPS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|z| !z.is_empty())
+            .map(String::from)
+            .collect();
+        let interval = std::env::var("POLL_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(300);
+
+        tokio::spawn(poll_worker(
+            client.clone(),
+            store_cache.clone(),
+            filters.clone(),
+            zips,
+            webhook_url,
+            Duration::from_secs(interval),
+        ));
+    }
+
+    let availability = warp::path!("availability" / String)
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::header::optional::<String>("accept"))
+        .map(move |s, query, accept| {
+            (
+                s,
+                query,
+                accept,
+                client.clone(),
+                store_cache.clone(),
+                filters.clone(),
+            )
+        })
         .untuple_one()
-        .and_then(availability)
+        .and_then(availability);
+
+    let metrics = warp::path!("metrics").and_then(metrics_handler);
+
+    let routes = availability
+        .or(metrics)
+        .recover(handle_rejection)
         .with(warp::trace(|info| {
             let mut host = "";
             if let Some(h) = info.host() {
@@ -143,83 +471,825 @@ async fn main() {
             )
         }));
 
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    match acme_domain {
+        Some(domain) => {
+            // Obtain (or load from cache) a certificate up front so the listener
+            // starts with a valid chain, then keep it fresh in the background.
+            let cert = acme::provision(&domain, &acme_cache_dir)
+                .await
+                .expect("obtaining an ACME certificate");
+
+            // A resolver the renewal task can swap in place, so a running
+            // listener presents the renewed certificate without a restart.
+            let resolver = Arc::new(acme::CertResolver::new());
+            resolver.install(&cert).expect("loading the issued certificate");
+
+            tokio::spawn(acme::renew_loop(domain, acme_cache_dir, resolver.clone()));
+
+            acme::serve_tls(routes, resolver, ([0, 0, 0, 0], 443).into())
+                .await
+                .expect("serving TLS");
+        }
+        None => {
+            warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+        }
+    }
+}
+
+/// Automatic certificate provisioning via ACME (Let's Encrypt). Modeled on the
+/// resolver/order flow in the mail-server crate: we drive an HTTP-01 order,
+/// persist the issued chain and account key under the cache directory, and
+/// re-run the order from a background task before the certificate expires so the
+/// service can be exposed publicly without a separate reverse proxy.
+mod acme {
+    use super::*;
+    use arc_swap::ArcSwapOption;
+    use rustls::server::{ClientHello, ResolvesServerCert};
+    use rustls::sign::CertifiedKey;
+    use std::time::Duration;
+
+    /// On-disk locations of an issued certificate and its private key.
+    pub struct Certificate {
+        pub cert_path: PathBuf,
+        pub key_path: PathBuf,
+    }
+
+    impl Certificate {
+        fn in_dir(cache_dir: &Path) -> Self {
+            Certificate {
+                cert_path: cache_dir.join("cert.pem"),
+                key_path: cache_dir.join("key.pem"),
+            }
+        }
+    }
+
+    /// A `ResolvesServerCert` whose certificate can be swapped in place. The
+    /// renewal task calls `install` after each successful order, so a running
+    /// TLS listener starts presenting the renewed chain without a restart.
+    pub struct CertResolver {
+        current: ArcSwapOption<CertifiedKey>,
+    }
+
+    impl CertResolver {
+        pub fn new() -> Self {
+            CertResolver {
+                current: ArcSwapOption::empty(),
+            }
+        }
+
+        /// Load the PEM chain and key at `cert`'s paths and make them the live
+        /// certificate for every subsequent handshake.
+        pub fn install(&self, cert: &Certificate) -> Result<(), anyhow::Error> {
+            let cert_pem = std::fs::read(&cert.cert_path)?;
+            let key_pem = std::fs::read(&cert.key_path)?;
+
+            let chain = rustls_pemfile::certs(&mut cert_pem.as_slice())?
+                .into_iter()
+                .map(rustls::Certificate)
+                .collect();
+            let key = rustls_pemfile::pkcs8_private_keys(&mut key_pem.as_slice())?
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("no private key in {:?}", cert.key_path))?;
+            let signing_key = rustls::sign::any_supported_type(&rustls::PrivateKey(key))?;
+
+            self.current
+                .store(Some(Arc::new(CertifiedKey::new(chain, signing_key))));
+            Ok(())
+        }
+    }
+
+    impl Default for CertResolver {
+        fn default() -> Self {
+            CertResolver::new()
+        }
+    }
+
+    impl ResolvesServerCert for CertResolver {
+        fn resolve(&self, _hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+            self.current.load_full()
+        }
+    }
+
+    /// Bind `addr` with TLS and serve `routes`, resolving the certificate
+    /// through `resolver` on every handshake so renewals take effect live.
+    pub async fn serve_tls<F>(
+        routes: F,
+        resolver: Arc<CertResolver>,
+        addr: SocketAddr,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: Filter + Clone + Send + Sync + 'static,
+        F::Extract: Reply,
+        F::Error: warp::reject::IsReject,
+    {
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let service = warp::service(routes);
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let acceptor = acceptor.clone();
+            let service = service.clone();
+
+            tokio::spawn(async move {
+                let tls = match acceptor.accept(stream).await {
+                    Ok(tls) => tls,
+                    Err(e) => {
+                        tracing::debug!(error = %e, "tls handshake failed");
+                        return;
+                    }
+                };
+
+                if let Err(e) = hyper::server::conn::Http::new()
+                    .serve_connection(tls, service)
+                    .await
+                {
+                    tracing::debug!(error = %e, "connection error");
+                }
+            });
+        }
+    }
+
+    /// Return a usable certificate for `domain`, reusing the cached chain when it
+    /// is still valid and otherwise driving a fresh ACME order.
+    pub async fn provision(domain: &str, cache_dir: &Path) -> Result<Certificate, anyhow::Error> {
+        tokio::fs::create_dir_all(cache_dir).await?;
+        let cert = Certificate::in_dir(cache_dir);
+
+        if !needs_renewal(&cert).await? {
+            tracing::info!(%domain, "reusing cached certificate");
+            return Ok(cert);
+        }
+
+        order(domain, &cert, cache_dir).await?;
+        Ok(cert)
+    }
+
+    /// Load the ACME account credentials cached under `cache_dir`, creating and
+    /// persisting a fresh account the first time so the same account key is
+    /// reused across restarts and renewals.
+    async fn load_or_create_account(
+        cache_dir: &Path,
+    ) -> Result<instant_acme::Account, anyhow::Error> {
+        use instant_acme::{Account, AccountCredentials, LetsEncrypt, NewAccount};
+
+        let path = cache_dir.join("account.json");
+        if path.exists() {
+            let data = tokio::fs::read(&path).await?;
+            let credentials: AccountCredentials = serde_json::from_slice(&data)?;
+            return Ok(Account::from_credentials(credentials).await?);
+        }
+
+        let (account, credentials) = Account::create(
+            &NewAccount {
+                contact: &[],
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            LetsEncrypt::Production.url(),
+            None,
+        )
+        .await?;
+
+        tokio::fs::write(&path, serde_json::to_vec(&credentials)?).await?;
+        Ok(account)
+    }
+
+    /// Drive a single HTTP-01 ACME order to completion, writing the resulting
+    /// chain and key to disk.
+    async fn order(
+        domain: &str,
+        cert: &Certificate,
+        cache_dir: &Path,
+    ) -> Result<(), anyhow::Error> {
+        use instant_acme::{
+            AuthorizationStatus, ChallengeType, Identifier, NewOrder, OrderStatus,
+        };
+
+        let account = load_or_create_account(cache_dir).await?;
+
+        let mut order = account
+            .new_order(&NewOrder {
+                identifiers: &[Identifier::Dns(domain.to_string())],
+            })
+            .await?;
+
+        let authorizations = order.authorizations().await?;
+        for authz in &authorizations {
+            if !matches!(authz.status, AuthorizationStatus::Pending) {
+                continue;
+            }
+
+            let challenge = authz
+                .challenges
+                .iter()
+                .find(|c| c.r#type == ChallengeType::Http01)
+                .ok_or_else(|| anyhow::anyhow!("no http-01 challenge offered"))?;
+
+            let token = challenge.token.clone();
+            let key_auth = order.key_authorization(challenge).as_str().to_string();
+
+            // Serve the key authorization at the well-known path until the
+            // order is validated, then tear the responder back down. The
+            // responder must be aborted even if validation fails or times
+            // out, or port 80 stays bound and every later renewal attempt
+            // panics trying to rebind it.
+            let responder = serve_challenge(token, key_auth);
+            let result = async {
+                order.set_challenge_ready(&challenge.url).await?;
+                wait_until_ready(&mut order).await
+            }
+            .await;
+            responder.abort();
+            result?;
+        }
+
+        let key = finalize(&mut order, domain).await?;
+        let chain = poll_certificate(&mut order).await?;
+
+        if !matches!(order.state().status, OrderStatus::Valid) {
+            anyhow::bail!("acme order did not reach the valid state");
+        }
+
+        tokio::fs::write(&cert.cert_path, chain).await?;
+        tokio::fs::write(&cert.key_path, key).await?;
+        Ok(())
+    }
+
+    async fn finalize(
+        order: &mut instant_acme::Order,
+        domain: &str,
+    ) -> Result<String, anyhow::Error> {
+        let mut params = rcgen::CertificateParams::new(vec![domain.to_string()]);
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let keypair = rcgen::Certificate::from_params(params)?;
+        let csr = keypair.serialize_request_der()?;
+
+        order.finalize(&csr).await?;
+        Ok(keypair.serialize_private_key_pem())
+    }
+
+    async fn poll_certificate(order: &mut instant_acme::Order) -> Result<String, anyhow::Error> {
+        let mut delay = Duration::from_millis(250);
+        for _ in 0..10 {
+            if let Some(chain) = order.certificate().await? {
+                return Ok(chain);
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+        anyhow::bail!("timed out waiting for the issued certificate")
+    }
+
+    async fn wait_until_ready(order: &mut instant_acme::Order) -> Result<(), anyhow::Error> {
+        use instant_acme::OrderStatus;
+
+        let mut delay = Duration::from_millis(250);
+        for _ in 0..10 {
+            let state = order.refresh().await?;
+            match state.status {
+                OrderStatus::Ready | OrderStatus::Valid => return Ok(()),
+                OrderStatus::Invalid => anyhow::bail!("acme order became invalid"),
+                _ => {
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+        anyhow::bail!("timed out waiting for challenge validation")
+    }
+
+    /// Spin up a throwaway listener on port 80 that answers the HTTP-01
+    /// challenge, returning a handle that aborts it once validation is done.
+    fn serve_challenge(token: String, key_auth: String) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let route = warp::path!(".well-known" / "acme-challenge" / String).map(
+                move |requested: String| {
+                    if requested == token {
+                        key_auth.clone()
+                    } else {
+                        String::new()
+                    }
+                },
+            );
+            warp::serve(route).run(([0, 0, 0, 0], 80)).await;
+        })
+    }
+
+    /// True when no certificate is cached yet or the cached one is within its
+    /// renewal window.
+    async fn needs_renewal(cert: &Certificate) -> Result<bool, anyhow::Error> {
+        if !cert.cert_path.exists() || !cert.key_path.exists() {
+            return Ok(true);
+        }
+
+        let pem = tokio::fs::read(&cert.cert_path).await?;
+        let (_, parsed) = x509_parser::pem::parse_x509_pem(&pem)?;
+        let x509 = parsed.parse_x509()?;
+        let remaining = x509.validity().time_to_expiration();
+
+        // Renew once we are inside the final third of a 90-day Let's Encrypt
+        // certificate's lifetime.
+        Ok(remaining
+            .map(|d| d < time::Duration::days(30))
+            .unwrap_or(true))
+    }
+
+    /// Background task that re-provisions the certificate daily once it enters
+    /// its renewal window and swaps the fresh chain into the live `resolver`.
+    pub async fn renew_loop(domain: String, cache_dir: PathBuf, resolver: Arc<CertResolver>) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            ticker.tick().await;
+            match provision(&domain, &cache_dir).await {
+                Ok(cert) => {
+                    if let Err(e) = resolver.install(&cert) {
+                        tracing::warn!(error = %e, "reloading renewed certificate failed");
+                    }
+                }
+                Err(e) => tracing::warn!(error = %e, "certificate renewal failed"),
+            }
+        }
+    }
 }
 
 async fn list_stores(
     zip_code: String,
     client: Client,
-    store_cache: Arc<DashMap<String, GetStoresResponse>>,
+    store_cache: Arc<StoreCache>,
+    filters: Arc<Filters>,
 ) -> Result<GetStoresResponse, Err> {
-    if let Some(stores) = store_cache.get(&zip_code) {
+    if !filters.zip_allowed(&zip_code) {
+        return Err(Err(anyhow::anyhow!("zip code {} is blocked", zip_code)));
+    }
+
+    if let Some(stores) = store_cache.get_fresh(&zip_code) {
+        metrics::CACHE_HITS.inc();
+        let span = info_span!("list stores", %zip_code, source="cache");
+        let _g = span.enter();
+
+        return Ok(stores);
+    }
+
+    // Coalesce concurrent refreshes for this ZIP: only the request holding the
+    // per-key lock performs the upstream fetch, and whoever loses the race
+    // re-checks the (now fresh) cache instead of issuing a duplicate request.
+    let lock = store_cache.lock_for(&zip_code);
+    let _guard = lock.lock().await;
+
+    if let Some(stores) = store_cache.get_fresh(&zip_code) {
+        metrics::CACHE_HITS.inc();
         let span = info_span!("list stores", %zip_code, source="cache");
         let _g = span.enter();
 
-        return Ok(stores.clone());
+        return Ok(stores);
     }
 
-    let response: GetStoresResponse = client
+    metrics::CACHE_MISSES.inc();
+    metrics::GET_STORES_TOTAL.inc();
+
+    let response: GetStoresResponse = fetch_get_stores(&client, &zip_code)
+        .instrument(info_span!("list stores", %zip_code, source="http"))
+        .await
+        .map_err(|e| {
+            metrics::record_upstream_error(&e);
+            Err::from(e)
+        })?;
+
+    store_cache.insert(zip_code, response.clone());
+
+    Ok(response)
+}
+
+async fn fetch_get_stores(
+    client: &Client,
+    zip_code: &str,
+) -> Result<GetStoresResponse, reqwest::Error> {
+    client
         .get("https://www.riteaid.com/services/ext/v2/stores/getStores")
         .query(&[
-            ("address", &zip_code[..]),
+            ("address", zip_code),
             ("attrFilter", "PREF-112"),
             ("fetchMechanismVersion", "2"),
             ("radius", "50"),
         ])
         .send()
-        .instrument(info_span!("list stores", %zip_code, source="http"))
         .await?
         .json()
-        .await?;
-
-    store_cache.insert(zip_code, response.clone());
+        .await
+}
 
-    Ok(response)
+/// True if the `Accept` header includes the NDJSON media type, tolerating a
+/// comma-separated list of qualified values (e.g. `application/x-ndjson,
+/// */*;q=0.1` or `application/x-ndjson; charset=utf-8`) rather than requiring
+/// an exact match against the whole header.
+fn accepts_ndjson(accept: Option<&str>) -> bool {
+    accept
+        .is_some_and(|h| h.split(',').any(|p| p.trim().starts_with("application/x-ndjson")))
 }
 
 async fn availability(
     zip_code: String,
+    query: HashMap<String, String>,
+    accept: Option<String>,
     client: Client,
-    store_cache: Arc<DashMap<String, GetStoresResponse>>,
-) -> Result<impl warp::Reply, Rejection> {
-    let response = list_stores(zip_code, client.clone(), store_cache)
+    store_cache: Arc<StoreCache>,
+    filters: Arc<Filters>,
+) -> Result<warp::reply::Response, Rejection> {
+    if !filters.zip_allowed(&zip_code) {
+        return Err(reject::custom(Blocked));
+    }
+
+    let metrics_zip = zip_code.clone();
+    let response = list_stores(zip_code, client.clone(), store_cache, filters.clone())
         .await
         .map_err(|e| reject::custom(e))?;
 
     let requests = FuturesUnordered::new();
     for store in response.data.stores {
+        if !filters.store_allowed(store.store_number) {
+            continue;
+        }
+
         let client = client.clone();
 
-        requests.push(async move {
-            let response: CheckSlotsResponse = client
-                .get("https://www.riteaid.com/services/ext/v2/vaccine/checkSlots")
-                .query(&[("storeNumber", store.store_number)])
-                .send()
-                .instrument(info_span!("get store availability"))
-                .await
-                .map_err(|e| reject::custom(Err::from(e)))?
-                .json()
-                .await
-                .map_err(|e| reject::custom(Err::from(e)))?;
-
-            let possible_availability = *response.data.slots.get("1").unwrap_or(&false)
-                && *response.data.slots.get("1").unwrap_or(&false)
-                && response.data.slots.len() == 2;
-
-            Ok::<_, Rejection>(AvailabilityResponse {
-                id: store.store_number,
-                address: store.address,
-                possible_availability,
-                zip: store.zip_code,
-                phone: store.phone,
+        requests.push(check_slots(client, store));
+    }
+
+    let streaming = query.get("stream").map(|v| v == "1").unwrap_or(false)
+        || accepts_ndjson(accept.as_deref());
+
+    if streaming {
+        // Emit each store's result as its own JSON line the instant its future
+        // resolves; a failing store's error is held back and emitted as a
+        // trailing error object after every store has resolved, rather than
+        // aborting the whole stream. Tally `possible_availability` as lines go
+        // by and push it into the gauge once the stream drains, so `?stream=1`
+        // keeps the metric fresh the same as the buffered path does. When no
+        // store failed, the trailing step yields `None` rather than an empty
+        // chunk: hyper frames every body item as its own chunk, and an empty
+        // `Ok(Vec::new())` here would put a stray `0\r\n\r\n` on the wire
+        // ahead of hyper's real end-of-body terminator, confusing a
+        // keep-alive client's framing of the next response.
+        let available = Arc::new(std::sync::atomic::AtomicI64::new(0));
+        let available_for_stream = available.clone();
+        let errors = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let errors_for_stream = errors.clone();
+        let lines = requests
+            .filter_map(move |result| {
+                let line = match result {
+                    Ok(resp) => {
+                        if resp.possible_availability {
+                            available_for_stream.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        }
+                        let mut line = serde_json::to_vec(&resp)
+                            .expect("serializing an availability line cannot fail");
+                        line.push(b'\n');
+                        Some(Ok::<_, std::convert::Infallible>(line))
+                    }
+                    Err(e) => {
+                        errors_for_stream.lock().unwrap().push(StreamError {
+                            error: e.to_string(),
+                        });
+                        None
+                    }
+                };
+                futures::future::ready(line)
             })
-        });
+            .chain(
+                futures::stream::once(async move {
+                    metrics::AVAILABLE_STORES
+                        .with_label_values(&[&metrics_zip])
+                        .set(available.load(std::sync::atomic::Ordering::Relaxed));
+                    let mut trailer = Vec::new();
+                    for error in errors.lock().unwrap().iter() {
+                        serde_json::to_writer(&mut trailer, error)
+                            .expect("serializing an error line cannot fail");
+                        trailer.push(b'\n');
+                    }
+                    if trailer.is_empty() {
+                        None
+                    } else {
+                        Some(Ok(trailer))
+                    }
+                })
+                .filter_map(futures::future::ready),
+            );
+
+        let body = hyper::Body::wrap_stream(lines);
+        return Ok(warp::http::Response::builder()
+            .header("content-type", "application/x-ndjson")
+            .body(body)
+            .expect("building the NDJSON response cannot fail"));
     }
 
     let response = requests
+        .map(|result| result.map_err(|e| reject::custom(Err(e))))
         .try_collect::<Vec<_>>()
         .instrument(info_span!("get all store availability"))
         .await?;
 
-    Ok(warp::reply::json(&response))
+    let available = response.iter().filter(|r| r.possible_availability).count();
+    metrics::AVAILABLE_STORES
+        .with_label_values(&[&metrics_zip])
+        .set(available as i64);
+
+    Ok(warp::reply::json(&response).into_response())
+}
+
+/// Background subsystem that re-runs the availability logic for a fixed set of
+/// ZIP codes on an interval and POSTs a webhook whenever a store's
+/// `possible_availability` rises from false to true. A `DashMap` of the
+/// last-seen value per store number lets us fire only on rising edges instead
+/// of on every poll, so a persistently-available store isn't re-announced.
+async fn poll_worker(
+    client: Client,
+    store_cache: Arc<StoreCache>,
+    filters: Arc<Filters>,
+    zips: Vec<String>,
+    webhook_url: String,
+    interval: Duration,
+) {
+    let last_seen: DashMap<i32, bool> = DashMap::new();
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let span = info_span!("poll cycle", watched_zips = zips.len());
+        if let Err(e) = poll_cycle(
+            &client,
+            &store_cache,
+            &filters,
+            &zips,
+            &last_seen,
+            &webhook_url,
+        )
+        .instrument(span)
+        .await
+        {
+            tracing::warn!(error = %e, "poll cycle failed");
+        }
+    }
+}
+
+async fn poll_cycle(
+    client: &Client,
+    store_cache: &Arc<StoreCache>,
+    filters: &Arc<Filters>,
+    zips: &[String],
+    last_seen: &DashMap<i32, bool>,
+    webhook_url: &str,
+) -> Result<(), anyhow::Error> {
+    for zip in zips {
+        // Skip a blocked/non-allow-listed ZIP the same way a blocked store is
+        // skipped below, so one misconfigured watch doesn't abort the cycle.
+        if !filters.zip_allowed(zip) {
+            continue;
+        }
+
+        let stores = list_stores(zip.clone(), client.clone(), store_cache.clone(), filters.clone())
+            .await
+            .map_err(|e| e.0)?;
+
+        let requests = FuturesUnordered::new();
+        for store in stores.data.stores {
+            if !filters.store_allowed(store.store_number) {
+                continue;
+            }
+
+            requests.push(check_slots(client.clone(), store));
+        }
+
+        let results: Vec<_> = requests.collect().await;
+        let mut available = 0i64;
+        for result in results {
+            let availability = match result {
+                Ok(a) => a,
+                Err(e) => {
+                    tracing::warn!(error = %e, "checkSlots failed during poll");
+                    continue;
+                }
+            };
+
+            if availability.possible_availability {
+                available += 1;
+            }
+
+            let previous = last_seen.get(&availability.id).map(|v| *v);
+            if is_rising_edge(previous, availability.possible_availability) {
+                if let Err(e) = notify(client, webhook_url, &availability).await {
+                    // Leave `last_seen` untouched so the rising edge is retried
+                    // next cycle rather than silently swallowed, and keep
+                    // processing the remaining stores and ZIPs.
+                    tracing::warn!(error = %e, store = availability.id, "webhook notification failed");
+                    continue;
+                }
+            }
+
+            last_seen.insert(availability.id, availability.possible_availability);
+        }
+
+        metrics::AVAILABLE_STORES.with_label_values(&[zip]).set(available);
+    }
+
+    Ok(())
+}
+
+/// True when `current` availability should trigger a webhook: it is available
+/// now but was not the last time we saw it (or we've never seen it before).
+fn is_rising_edge(previous: Option<bool>, current: bool) -> bool {
+    current && !previous.unwrap_or(false)
+}
+
+async fn notify(
+    client: &Client,
+    webhook_url: &str,
+    availability: &AvailabilityResponse,
+) -> Result<(), anyhow::Error> {
+    client
+        .post(webhook_url)
+        .json(availability)
+        .send()
+        .instrument(info_span!("notify webhook", store = availability.id))
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Map our custom rejections onto HTTP responses; a blocked ZIP becomes a
+/// `403 Forbidden` and everything else falls through to warp's defaults.
+async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Rejection> {
+    if err.find::<Blocked>().is_some() {
+        return Ok(warp::http::Response::builder()
+            .status(warp::http::StatusCode::FORBIDDEN)
+            .body("zip code is blocked".to_string()));
+    }
+
+    Err(err)
+}
+
+async fn check_slots(client: Client, store: Store) -> Result<AvailabilityResponse, anyhow::Error> {
+    metrics::CHECK_SLOTS_TOTAL.inc();
+    let timer = metrics::CHECK_SLOTS_LATENCY.start_timer();
+
+    let response: CheckSlotsResponse = async {
+        client
+            .get("https://www.riteaid.com/services/ext/v2/vaccine/checkSlots")
+            .query(&[("storeNumber", store.store_number)])
+            .send()
+            .await?
+            .json()
+            .await
+    }
+    .instrument(info_span!("get store availability"))
+    .await
+    .map_err(|e: reqwest::Error| {
+        metrics::record_upstream_error(&e);
+        e
+    })?;
+
+    timer.observe_duration();
+
+    let possible_availability = *response.data.slots.get("1").unwrap_or(&false)
+        && *response.data.slots.get("1").unwrap_or(&false)
+        && response.data.slots.len() == 2;
+
+    Ok(AvailabilityResponse {
+        id: store.store_number,
+        address: store.address,
+        possible_availability,
+        zip: store.zip_code,
+        phone: store.phone,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(allow_zip: Vec<&str>, block_zip: Vec<&str>, block_store: Vec<i32>) -> Filters {
+        Filters::from_args(Args {
+            allow_zip: allow_zip.into_iter().map(String::from).collect(),
+            block_zip: block_zip.into_iter().map(String::from).collect(),
+            block_store,
+            acme_domain: None,
+            acme_cache_dir: PathBuf::from("acme-cache"),
+        })
+    }
+
+    #[test]
+    fn zip_allowed_with_no_allow_list_permits_anything_not_blocked() {
+        let filters = filters(vec![], vec!["99999"], vec![]);
+        assert!(filters.zip_allowed("12345"));
+        assert!(!filters.zip_allowed("99999"));
+    }
+
+    #[test]
+    fn zip_allowed_restricts_to_the_allow_list() {
+        let filters = filters(vec!["12345"], vec![], vec![]);
+        assert!(filters.zip_allowed("12345"));
+        assert!(!filters.zip_allowed("54321"));
+    }
+
+    #[test]
+    fn block_zip_overrides_the_allow_list() {
+        let filters = filters(vec!["12345"], vec!["12345"], vec![]);
+        assert!(!filters.zip_allowed("12345"));
+    }
+
+    #[test]
+    fn store_allowed_rejects_only_blocked_store_numbers() {
+        let filters = filters(vec![], vec![], vec![42]);
+        assert!(!filters.store_allowed(42));
+        assert!(filters.store_allowed(7));
+    }
+
+    fn empty_response() -> GetStoresResponse {
+        GetStoresResponse {
+            data: GetStoresData { stores: vec![] },
+        }
+    }
+
+    #[test]
+    fn get_fresh_returns_none_before_insert_and_some_after() {
+        let cache = StoreCache::new(Duration::from_secs(60));
+        assert!(cache.get_fresh("12345").is_none());
+
+        cache.insert("12345".to_string(), empty_response());
+        assert!(cache.get_fresh("12345").is_some());
+    }
+
+    #[test]
+    fn get_fresh_reports_a_miss_once_the_ttl_has_elapsed() {
+        let cache = StoreCache::new(Duration::from_millis(10));
+        cache.insert("12345".to_string(), empty_response());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(cache.get_fresh("12345").is_none());
+    }
+
+    #[test]
+    fn lock_for_returns_the_same_lock_for_the_same_zip() {
+        let cache = StoreCache::new(Duration::from_secs(60));
+        let a = cache.lock_for("12345");
+        let b = cache.lock_for("12345");
+        assert!(Arc::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn sweep_evicts_expired_entries_and_unheld_locks() {
+        let cache = StoreCache::new(Duration::from_millis(10));
+        cache.insert("12345".to_string(), empty_response());
+        cache.lock_for("12345"); // not held outside the map, should be swept
+        let _held_lock = cache.lock_for("99999"); // held, must survive
+
+        std::thread::sleep(Duration::from_millis(20));
+        cache.sweep();
+
+        assert!(cache.entries.is_empty());
+        assert!(!cache.locks.contains_key("12345"));
+        // The lock for "99999" is still held by `_held_lock`, so sweep must not
+        // drop it even though its ZIP has no cache entry.
+        assert!(cache.locks.contains_key("99999"));
+    }
+
+    #[test]
+    fn is_rising_edge_fires_on_first_sighting() {
+        assert!(is_rising_edge(None, true));
+    }
+
+    #[test]
+    fn is_rising_edge_does_not_fire_while_unavailable() {
+        assert!(!is_rising_edge(None, false));
+        assert!(!is_rising_edge(Some(false), false));
+    }
+
+    #[test]
+    fn is_rising_edge_does_not_renotify_sustained_availability() {
+        assert!(!is_rising_edge(Some(true), true));
+    }
+
+    #[test]
+    fn is_rising_edge_renotifies_after_a_down_up_flap() {
+        assert!(is_rising_edge(Some(false), true));
+    }
+
+    #[test]
+    fn is_rising_edge_keeps_firing_while_last_seen_is_stale() {
+        // `poll_cycle` skips the `last_seen` insert when the webhook for a
+        // rising edge fails, so a failed notify leaves `previous` at its old value and
+        // the same edge is evaluated again next cycle instead of going
+        // permanently silent.
+        let stale_previous = Some(false);
+        assert!(is_rising_edge(stale_previous, true));
+        assert!(is_rising_edge(stale_previous, true));
+    }
 }